@@ -0,0 +1,46 @@
+/// Well-known Maelstrom error codes, sent in a `Payload::Error`'s `code` field.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u16)]
+pub enum ErrorCode {
+    Timeout = 0,
+    NodeNotFound = 1,
+    NotSupported = 10,
+    TemporarilyUnavailable = 11,
+    MalformedRequest = 12,
+    Crash = 13,
+    Abort = 14,
+    KeyDoesNotExist = 20,
+    KeyAlreadyExists = 21,
+    PreconditionFailed = 22,
+    TxnConflict = 30,
+}
+
+impl ErrorCode {
+    /// Whether retrying is pointless: the peer told us something that won't
+    /// change on its own, as opposed to a transient `Timeout` or
+    /// `TemporarilyUnavailable` that's worth trying again.
+    pub fn is_definite(self) -> bool {
+        !matches!(self, ErrorCode::Timeout | ErrorCode::TemporarilyUnavailable)
+    }
+}
+
+impl TryFrom<u16> for ErrorCode {
+    type Error = ();
+
+    fn try_from(code: u16) -> Result<Self, Self::Error> {
+        Ok(match code {
+            0 => ErrorCode::Timeout,
+            1 => ErrorCode::NodeNotFound,
+            10 => ErrorCode::NotSupported,
+            11 => ErrorCode::TemporarilyUnavailable,
+            12 => ErrorCode::MalformedRequest,
+            13 => ErrorCode::Crash,
+            14 => ErrorCode::Abort,
+            20 => ErrorCode::KeyDoesNotExist,
+            21 => ErrorCode::KeyAlreadyExists,
+            22 => ErrorCode::PreconditionFailed,
+            30 => ErrorCode::TxnConflict,
+            _ => return Err(()),
+        })
+    }
+}
@@ -0,0 +1,59 @@
+use anyhow::Result;
+use serde_json::Value;
+
+use crate::{Message, Node, Payload};
+
+/// A client for one of Maelstrom's built-in key/value services.
+pub struct Kv<'a> {
+    node: &'a mut Node,
+    service: &'static str,
+}
+
+impl<'a> Kv<'a> {
+    pub fn seq(node: &'a mut Node) -> Self {
+        Kv { node, service: "seq-kv" }
+    }
+
+    pub fn lin(node: &'a mut Node) -> Self {
+        Kv { node, service: "lin-kv" }
+    }
+
+    pub fn lww(node: &'a mut Node) -> Self {
+        Kv { node, service: "lww-kv" }
+    }
+
+    pub fn read(&mut self, key: String, callback: impl FnOnce(&mut Node, Message) + 'static) -> Result<()> {
+        self.node
+            .rpc(self.service.to_string(), Payload::Read { key: Some(key) }, callback)
+    }
+
+    pub fn write(
+        &mut self,
+        key: String,
+        value: Value,
+        callback: impl FnOnce(&mut Node, Message) + 'static,
+    ) -> Result<()> {
+        self.node
+            .rpc(self.service.to_string(), Payload::Write { key, value }, callback)
+    }
+
+    pub fn cas(
+        &mut self,
+        key: String,
+        from: Value,
+        to: Value,
+        create_if_not_exists: bool,
+        callback: impl FnOnce(&mut Node, Message) + 'static,
+    ) -> Result<()> {
+        self.node.rpc(
+            self.service.to_string(),
+            Payload::Cas {
+                key,
+                from,
+                to,
+                create_if_not_exists,
+            },
+            callback,
+        )
+    }
+}
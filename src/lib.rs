@@ -1,8 +1,17 @@
-use std::io::{StdinLock, StdoutLock, Write};
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Write};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
 
 use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
-use serde_json::Deserializer;
+use serde_json::{Deserializer, Value};
+
+mod error;
+mod kv;
+pub use error::ErrorCode;
+pub use kv::Kv;
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Message {
@@ -11,6 +20,34 @@ pub struct Message {
     body: Body,
 }
 
+impl Message {
+    /// Builds the tick fed into `Node::backdoor()`'s sender to trigger a
+    /// `Node::gossip()` retransmission pass from a background timer thread.
+    pub fn gossip_tick() -> Message {
+        Message {
+            src: String::new(),
+            dest: String::new(),
+            body: Body {
+                msg_id: None,
+                in_reply_to: None,
+                payload: Payload::Gossip,
+            },
+        }
+    }
+
+    fn shutdown(error: Option<String>) -> Message {
+        Message {
+            src: String::new(),
+            dest: String::new(),
+            body: Body {
+                msg_id: None,
+                in_reply_to: None,
+                payload: Payload::Shutdown { error },
+            },
+        }
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 struct Body {
     msg_id: Option<i32>,
@@ -22,7 +59,7 @@ struct Body {
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
-enum Payload {
+pub enum Payload {
     Init {
         node_id: String,
         node_ids: Vec<String>,
@@ -39,69 +76,198 @@ enum Payload {
         id: String,
     },
     Topology {
-        node_ids: Vec<String>,
+        topology: HashMap<String, Vec<String>>,
     },
     TopologyOk,
     Broadcast {
         message: i32,
     },
     BroadcastOk,
-    Read,
+    // The broadcast workload's own `read` (no key) and a kv service's `read`
+    // of one key share the same wire type, so both live in one variant.
+    Read {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        key: Option<String>,
+    },
     ReadOk {
-        messages: Vec<i32>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        messages: Option<Vec<i32>>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        value: Option<Value>,
+    },
+    Write {
+        key: String,
+        value: Value,
+    },
+    WriteOk,
+    Cas {
+        key: String,
+        from: Value,
+        to: Value,
+        create_if_not_exists: bool,
     },
+    CasOk,
+    Error {
+        code: u16,
+        text: String,
+    },
+    // Never sent over the wire: a tick injected through the backdoor sender to
+    // drive periodic work such as gossip retransmission.
+    Gossip,
+    // Never sent over the wire: tells `run()` the reader thread has stopped
+    // (stdin closed, or a line failed to parse) so it can return instead of
+    // blocking forever on a channel the backdoor sender keeps open.
+    Shutdown { error: Option<String> },
 }
 
-pub struct Node<'a> {
+type Callback = Box<dyn FnOnce(&mut Node, Message)>;
+type OnInit = Box<dyn FnOnce(&Node)>;
+
+pub struct Node {
     messages: Vec<i32>,
+    seen: HashSet<i32>,
+    topology: HashMap<String, Vec<String>>,
+    pending: HashMap<String, HashSet<i32>>,
+    in_flight: HashMap<(String, i32), i32>,
+    callbacks: HashMap<i32, Callback>,
     next_msg_id: i32,
     node_id: Option<String>,
     node_ids: Option<Vec<String>>,
-    stdout: StdoutLock<'a>,
+    stdout: Arc<Mutex<io::Stdout>>,
+    backdoor: Option<Sender<Message>>,
+    on_init: Option<OnInit>,
+}
+
+impl Default for Node {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
-impl Node<'_> {
-    pub fn new(stdout: StdoutLock) -> Node {
+impl Node {
+    pub fn new() -> Node {
         Node {
             messages: vec![],
+            seen: HashSet::new(),
+            topology: HashMap::new(),
+            pending: HashMap::new(),
+            in_flight: HashMap::new(),
+            callbacks: HashMap::new(),
             next_msg_id: 0,
             node_id: None,
             node_ids: None,
-            stdout,
+            stdout: Arc::new(Mutex::new(io::stdout())),
+            backdoor: None,
+            on_init: None,
         }
     }
 
-    pub fn run(&mut self, stdin: StdinLock) -> Result<()> {
-        let strin = Deserializer::from_reader(stdin).into_iter::<Message>();
-        for msg in strin {
-            let msg = msg.context("STDIN could not be deserialized")?;
+    /// Registers a hook run once, right after `Init`, so callers can grab the
+    /// backdoor sender and spawn background timer threads.
+    pub fn on_init(&mut self, f: impl FnOnce(&Node) + 'static) {
+        self.on_init = Some(Box::new(f));
+    }
+
+    /// A cloneable sender into the same queue the handler drains, so
+    /// background threads can inject synthetic messages. Typical use, from
+    /// inside an `on_init` hook, is a timer thread that periodically sends
+    /// `Message::gossip_tick()` to drive `gossip()` retransmission:
+    ///
+    /// ```ignore
+    /// node.on_init(|node| {
+    ///     let backdoor = node.backdoor();
+    ///     std::thread::spawn(move || loop {
+    ///         std::thread::sleep(std::time::Duration::from_millis(300));
+    ///         if backdoor.send(Message::gossip_tick()).is_err() {
+    ///             return;
+    ///         }
+    ///     });
+    /// });
+    /// ```
+    pub fn backdoor(&self) -> Sender<Message> {
+        self.backdoor
+            .clone()
+            .expect("backdoor requested before run() started")
+    }
+
+    pub fn run(&mut self) -> Result<()> {
+        let (tx, rx) = mpsc::channel::<Message>();
+        self.backdoor = Some(tx.clone());
+
+        thread::spawn(move || {
+            let stdin = io::stdin();
+            let messages = Deserializer::from_reader(stdin.lock()).into_iter::<Message>();
+            let mut error = None;
+            for msg in messages {
+                match msg {
+                    Ok(msg) => {
+                        if tx.send(msg).is_err() {
+                            return;
+                        }
+                    }
+                    Err(err) => {
+                        error = Some(err.to_string());
+                        break;
+                    }
+                }
+            }
+            let _ = tx.send(Message::shutdown(error));
+        });
+
+        for msg in rx {
+            if let Payload::Shutdown { error } = msg.body.payload {
+                return match error {
+                    Some(text) => Err(anyhow::Error::msg(text)).context("STDIN could not be deserialized"),
+                    None => Ok(()),
+                };
+            }
             self.handle(msg).context("handler failed")?;
         }
         Ok(())
     }
 
     pub fn handle(&mut self, msg: Message) -> Result<()> {
+        if let Some(id) = msg.body.in_reply_to {
+            if let Some(callback) = self.callbacks.remove(&id) {
+                callback(self, msg);
+                return Ok(());
+            }
+        }
+
         match msg.body.payload.clone() {
             Payload::Echo { echo } => self.reply(msg, Payload::EchoOk { echo }),
             Payload::Init { node_id, node_ids } => {
                 self.init(node_id, node_ids).context("failed to init")?;
+                if let Some(on_init) = self.on_init.take() {
+                    on_init(self);
+                }
                 self.reply(msg, Payload::InitOk)
             }
-            Payload::Generate { .. } => self.reply(
+            Payload::Generate => self.reply(
                 msg,
                 Payload::GenerateOk {
                     id: self.generate_id(),
                 },
             ),
-            Payload::Topology { .. } => self.reply(msg, Payload::Topology { node_ids: vec![] }),
-            Payload::Broadcast { .. } => self.reply(msg, Payload::BroadcastOk),
-            Payload::Read { .. } => self.reply(
+            Payload::Topology { topology } => {
+                self.topology = topology;
+                self.reply(msg, Payload::TopologyOk)
+            }
+            Payload::Broadcast { message } => {
+                self.receive_broadcast(message)?;
+                self.reply(msg, Payload::BroadcastOk)
+            }
+            // Keyed reads are requests *we* send to a kv service, never ones we receive.
+            Payload::Read { key: None } => self.reply(
                 msg,
                 Payload::ReadOk {
-                    messages: self.messages.clone(),
+                    messages: Some(self.messages.clone()),
+                    value: None,
                 },
             ),
-            _ => Ok(()), // ignore "oks" from other nodes
+            Payload::Read { key: Some(_) } => Ok(()),
+            Payload::Gossip => self.gossip(),
+            _ => Ok(()), // ignore "oks" and unsolicited errors from other nodes
         }
     }
 
@@ -116,23 +282,128 @@ impl Node<'_> {
         format!("{}-{}", n, self.next_msg_id)
     }
 
+    /// Sends `payload` to an arbitrary node, independent of any message being replied to.
+    pub fn send(&mut self, dest: String, payload: Payload) -> Result<()> {
+        let src = self.node_id.clone().expect("sending before init");
+        self.write_message(src, dest, None, payload)
+    }
+
+    /// Sends `payload` to `dest` and invokes `callback` with the reply once one
+    /// arrives, correlated by `msg_id`/`in_reply_to`.
+    pub fn rpc(
+        &mut self,
+        dest: String,
+        payload: Payload,
+        callback: impl FnOnce(&mut Node, Message) + 'static,
+    ) -> Result<()> {
+        let msg_id = self.next_msg_id;
+        self.callbacks.insert(msg_id, Box::new(callback));
+        self.send(dest, payload)
+    }
+
     fn reply(&mut self, msg: Message, payload: Payload) -> Result<()> {
-        let reply = Message {
-            src: msg.dest,
-            dest: msg.src,
+        self.write_message(msg.dest, msg.src, msg.body.msg_id, payload)
+    }
+
+    /// Replies to `msg` with a Maelstrom error, mirroring `reply`.
+    pub fn reply_error(&mut self, msg: Message, code: ErrorCode, text: impl Into<String>) -> Result<()> {
+        self.reply(
+            msg,
+            Payload::Error {
+                code: code as u16,
+                text: text.into(),
+            },
+        )
+    }
+
+    fn write_message(
+        &mut self,
+        src: String,
+        dest: String,
+        in_reply_to: Option<i32>,
+        payload: Payload,
+    ) -> Result<()> {
+        let msg = Message {
+            src,
+            dest,
             body: Body {
                 msg_id: Some(self.next_msg_id),
-                in_reply_to: msg.body.msg_id,
+                in_reply_to,
                 payload,
             },
         };
 
-        serde_json::to_writer(&mut self.stdout, &reply).context("serialize reply")?;
-        self.stdout
+        let mut stdout = self.stdout.lock().expect("stdout mutex poisoned");
+        serde_json::to_writer(&mut *stdout, &msg).context("serialize message")?;
+        stdout
             .write_all(b"\n")
-            .context("add trailing newline to replies")?;
+            .context("add trailing newline to messages")?;
 
         self.next_msg_id += 1;
         Ok(())
     }
+
+    fn neighbors(&self) -> Vec<String> {
+        let n = self.node_id.as_ref().expect("broadcasting before init");
+        self.topology.get(n).cloned().unwrap_or_default()
+    }
+
+    fn receive_broadcast(&mut self, message: i32) -> Result<()> {
+        if !self.seen.insert(message) {
+            return Ok(());
+        }
+        self.messages.push(message);
+
+        for peer in self.neighbors() {
+            self.pending.entry(peer.clone()).or_default().insert(message);
+            self.send_broadcast(peer, message)?;
+        }
+        Ok(())
+    }
+
+    /// Retransmits every value a peer hasn't acknowledged yet. Intended to be
+    /// driven by a periodic timer so broadcasts survive dropped messages.
+    pub fn gossip(&mut self) -> Result<()> {
+        for (peer, values) in self.pending.clone() {
+            for message in values {
+                self.send_broadcast(peer.clone(), message)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// At most one outstanding RPC per `(peer, message)`: if the previous tick's
+    /// request hasn't been answered yet, its callback is dropped before the
+    /// value is sent again, so an unresponsive peer doesn't stack callbacks
+    /// that would never get cleaned up. A late reply to a superseded request
+    /// is ignored; only the newest request's reply settles the value.
+    fn send_broadcast(&mut self, peer: String, message: i32) -> Result<()> {
+        let key = (peer.clone(), message);
+        if let Some(old_id) = self.in_flight.remove(&key) {
+            self.callbacks.remove(&old_id);
+        }
+        self.in_flight.insert(key, self.next_msg_id);
+
+        let acked_peer = peer.clone();
+        self.rpc(peer, Payload::Broadcast { message }, move |node, reply| {
+            node.in_flight.remove(&(acked_peer.clone(), message));
+
+            // BroadcastOk: the peer has it, stop retransmitting. A definite
+            // Error (e.g. NodeNotFound): retrying won't help either, so give
+            // up the same way. Anything else (including an indefinite error
+            // like Timeout) leaves it pending for the next gossip() tick.
+            let give_up = match reply.body.payload {
+                Payload::BroadcastOk => true,
+                Payload::Error { code, .. } => {
+                    ErrorCode::try_from(code).map(ErrorCode::is_definite).unwrap_or(true)
+                }
+                _ => false,
+            };
+            if give_up {
+                if let Some(pending) = node.pending.get_mut(&acked_peer) {
+                    pending.remove(&message);
+                }
+            }
+        })
+    }
 }